@@ -0,0 +1,239 @@
+// Copyright (c) 2019, Arm Limited, All Rights Reserved
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//          http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transport-level body compression, negotiated through the free bits of
+//! `WireHeader`'s `content_type`/`accept_type` fields: a client advertises
+//! what it can decode via `accept_type`, a service states how it actually
+//! encoded the body via `content_type`.
+use crate::requests::common::wire_header_1_0::WireHeader;
+
+/// Mask over the low bits of `content_type`/`accept_type` reserved for the
+/// body encoding. The remaining, higher bits are free for other content-type
+/// uses and are left untouched by the methods below.
+const ENCODING_MASK: u8 = 0b0000_0011;
+
+/// Transport-level encoding applied to a request or response body.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BodyEncoding {
+    /// Body bytes are sent as-is.
+    Identity,
+    /// Body is compressed with DEFLATE. Only usable with the `deflate` feature.
+    Deflate,
+    /// Body is compressed with Brotli. Only usable with the `brotli` feature.
+    Brotli,
+}
+
+impl BodyEncoding {
+    fn from_bits(bits: u8) -> Option<BodyEncoding> {
+        match bits & ENCODING_MASK {
+            0 => Some(BodyEncoding::Identity),
+            1 => Some(BodyEncoding::Deflate),
+            2 => Some(BodyEncoding::Brotli),
+            _ => None,
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            BodyEncoding::Identity => 0,
+            BodyEncoding::Deflate => 1,
+            BodyEncoding::Brotli => 2,
+        }
+    }
+}
+
+/// Pick the encoding a service should answer with, given what the request
+/// declared it accepts: `preferred` if `accepted` matches it, `Identity`
+/// otherwise. A service must never emit an encoding the requester did not
+/// advertise support for.
+pub fn negotiate_encoding(accepted: BodyEncoding, preferred: BodyEncoding) -> BodyEncoding {
+    if accepted == preferred {
+        preferred
+    } else {
+        BodyEncoding::Identity
+    }
+}
+
+impl WireHeader {
+    /// The encoding the requester declared it can decode, read from the low
+    /// bits of `accept_type`. `None` if those bits don't map to a known
+    /// encoding.
+    pub fn accepted_encoding(&self) -> Option<BodyEncoding> {
+        BodyEncoding::from_bits(self.accept_type)
+    }
+
+    /// Record, in the low bits of `accept_type`, the encoding the requester
+    /// can decode.
+    pub fn set_accepted_encoding(&mut self, encoding: BodyEncoding) {
+        self.accept_type = (self.accept_type & !ENCODING_MASK) | encoding.to_bits();
+    }
+
+    /// The encoding the body was actually sent with, read from the low bits
+    /// of `content_type`. `None` if those bits don't map to a known encoding.
+    pub fn content_encoding(&self) -> Option<BodyEncoding> {
+        BodyEncoding::from_bits(self.content_type)
+    }
+
+    /// Record, in the low bits of `content_type`, the encoding the body is
+    /// actually sent with. Callers should only set a non-`Identity` value
+    /// here once they've checked it against the request's
+    /// [`WireHeader::accepted_encoding`], e.g. via [`negotiate_encoding`].
+    pub fn set_content_encoding(&mut self, encoding: BodyEncoding) {
+        self.content_type = (self.content_type & !ENCODING_MASK) | encoding.to_bits();
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "testing")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_the_accepted_and_content_encodings_without_touching_other_bits() {
+        let mut header = WireHeader::new();
+        // Set bits outside the encoding mask to make sure they survive.
+        header.accept_type = 0b1111_0000;
+        header.content_type = 0b1111_0000;
+
+        header.set_accepted_encoding(BodyEncoding::Brotli);
+        header.set_content_encoding(BodyEncoding::Deflate);
+
+        assert_eq!(header.accepted_encoding(), Some(BodyEncoding::Brotli));
+        assert_eq!(header.content_encoding(), Some(BodyEncoding::Deflate));
+        assert_eq!(header.accept_type & 0b1111_0000, 0b1111_0000);
+        assert_eq!(header.content_type & 0b1111_0000, 0b1111_0000);
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_identity_when_not_accepted() {
+        assert_eq!(
+            negotiate_encoding(BodyEncoding::Identity, BodyEncoding::Brotli),
+            BodyEncoding::Identity
+        );
+        assert_eq!(
+            negotiate_encoding(BodyEncoding::Deflate, BodyEncoding::Deflate),
+            BodyEncoding::Deflate
+        );
+    }
+}
+
+#[cfg(feature = "deflate")]
+pub use deflate_codec::{DeflateReader, DeflateWriter};
+
+#[cfg(feature = "deflate")]
+mod deflate_codec {
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+
+    /// Wraps a `Write`, compressing everything written to it with DEFLATE
+    /// before passing it on to the underlying stream.
+    pub struct DeflateWriter<W: Write>(DeflateEncoder<W>);
+
+    impl<W: Write> DeflateWriter<W> {
+        /// Wrap `stream`, compressing with the default compression level.
+        pub fn new(stream: W) -> Self {
+            DeflateWriter(DeflateEncoder::new(stream, Compression::default()))
+        }
+    }
+
+    impl<W: Write> Write for DeflateWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    /// Wraps a `Read`, decompressing everything read from the underlying
+    /// stream as DEFLATE.
+    pub struct DeflateReader<R: Read>(DeflateDecoder<R>);
+
+    impl<R: Read> DeflateReader<R> {
+        /// Wrap `stream`, decoding it as a DEFLATE byte stream.
+        pub fn new(stream: R) -> Self {
+            DeflateReader(DeflateDecoder::new(stream))
+        }
+    }
+
+    impl<R: Read> Read for DeflateReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+}
+
+#[cfg(feature = "brotli")]
+pub use brotli_codec::{BrotliReader, BrotliWriter};
+
+#[cfg(feature = "brotli")]
+mod brotli_codec {
+    use std::io::{Read, Write};
+
+    /// Buffer size, in bytes, used internally by the Brotli encoder/decoder.
+    const BROTLI_BUFFER_SIZE: usize = 4096;
+    /// Default Brotli quality level (0-11); picked for a speed/ratio balance
+    /// suited to interactive request/response bodies.
+    const BROTLI_QUALITY: u32 = 5;
+    /// Default Brotli window size (log2 of the LZ77 window).
+    const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+
+    /// Wraps a `Write`, compressing everything written to it with Brotli
+    /// before passing it on to the underlying stream.
+    pub struct BrotliWriter<W: Write>(brotli::CompressorWriter<W>);
+
+    impl<W: Write> BrotliWriter<W> {
+        /// Wrap `stream`, compressing with this crate's default quality and
+        /// window size.
+        pub fn new(stream: W) -> Self {
+            BrotliWriter(brotli::CompressorWriter::new(
+                stream,
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_LG_WINDOW_SIZE,
+            ))
+        }
+    }
+
+    impl<W: Write> Write for BrotliWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    /// Wraps a `Read`, decompressing everything read from the underlying
+    /// stream as Brotli.
+    pub struct BrotliReader<R: Read>(brotli::Decompressor<R>);
+
+    impl<R: Read> BrotliReader<R> {
+        /// Wrap `stream`, decoding it as a Brotli byte stream.
+        pub fn new(stream: R) -> Self {
+            BrotliReader(brotli::Decompressor::new(stream, BROTLI_BUFFER_SIZE))
+        }
+    }
+
+    impl<R: Read> Read for BrotliReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2019, Arm Limited, All Rights Reserved
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may
+// not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//          http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Chunked framing for request/response bodies whose length is not known
+//! ahead of time, or that would not fit in `WireHeader::body_len`'s `u32`.
+//!
+//! A chunked body is a sequence of little-endian `u32` length-prefixed
+//! chunks, terminated by a zero-length chunk.
+use crate::requests::Result;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+/// `WireHeader::body_len` value reserved to mean "this body is chunked",
+/// rather than carrying the body's length directly.
+pub const CHUNKED_BODY_LEN: u32 = 0xFFFF_FFFF;
+
+/// Default cap, in bytes, on the size of a single chunk a `ChunkedBodyReader`
+/// will buffer before handing it back to the caller.
+const DEFAULT_MAX_CHUNK_SIZE: u32 = 1_048_576;
+
+/// Reads a chunked body off of the wire, presenting the concatenated chunk
+/// payloads to callers through the standard `Read` trait.
+pub struct ChunkedBodyReader<R: Read> {
+    stream: R,
+    max_chunk_size: u32,
+    current_chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> ChunkedBodyReader<R> {
+    /// Create a reader with the default maximum chunk size.
+    pub fn new(stream: R) -> Self {
+        ChunkedBodyReader::with_max_chunk_size(stream, DEFAULT_MAX_CHUNK_SIZE)
+    }
+
+    /// Create a reader that refuses to buffer a chunk larger than `max_chunk_size`
+    /// bytes, to bound memory use when reading from an untrusted peer.
+    pub fn with_max_chunk_size(stream: R, max_chunk_size: u32) -> Self {
+        ChunkedBodyReader {
+            stream,
+            max_chunk_size,
+            current_chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    fn read_next_chunk(&mut self) -> std::io::Result<()> {
+        let mut len_bytes = [0_u8; 4];
+        self.stream.read_exact(&mut len_bytes)?;
+        let len = u32::from_le_bytes(len_bytes);
+
+        if len == 0 {
+            self.finished = true;
+            self.current_chunk.clear();
+            self.position = 0;
+            return Ok(());
+        }
+
+        if len > self.max_chunk_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "chunk of {} bytes exceeds the maximum of {} bytes",
+                    len, self.max_chunk_size
+                ),
+            ));
+        }
+
+        let mut chunk = vec![0_u8; usize::try_from(len).unwrap_or(usize::MAX)];
+        self.stream.read_exact(&mut chunk)?;
+        self.current_chunk = chunk;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkedBodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.current_chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_next_chunk()?;
+            if self.finished {
+                return Ok(0);
+            }
+        }
+
+        let available = &self.current_chunk[self.position..];
+        let to_copy = std::cmp::min(available.len(), buf.len());
+        buf[..to_copy].copy_from_slice(&available[..to_copy]);
+        self.position += to_copy;
+        Ok(to_copy)
+    }
+}
+
+/// Splits whatever is written to it into chunks of at most `max_chunk_size`
+/// bytes, each prefixed with its little-endian `u32` length, and writes the
+/// terminating zero-length chunk when [`ChunkedBodyWriter::finish`] is called.
+pub struct ChunkedBodyWriter<W: Write> {
+    stream: W,
+    max_chunk_size: u32,
+}
+
+impl<W: Write> ChunkedBodyWriter<W> {
+    /// Create a writer with the default maximum chunk size.
+    pub fn new(stream: W) -> Self {
+        ChunkedBodyWriter::with_max_chunk_size(stream, DEFAULT_MAX_CHUNK_SIZE)
+    }
+
+    /// Create a writer that splits its input into chunks of at most
+    /// `max_chunk_size` bytes.
+    pub fn with_max_chunk_size(stream: W, max_chunk_size: u32) -> Self {
+        ChunkedBodyWriter {
+            stream,
+            max_chunk_size,
+        }
+    }
+
+    /// Write the terminating zero-length chunk, signalling the end of the body.
+    ///
+    /// # Errors
+    /// - if writing to the underlying stream fails, `ResponseStatus::ConnectionError` is returned.
+    pub fn finish(mut self) -> Result<()> {
+        self.stream.write_all(&0_u32.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkedBodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            // A zero-length chunk is the stream terminator written by `finish`;
+            // an empty write must not emit one early.
+            return Ok(0);
+        }
+
+        let max_chunk_size = usize::try_from(self.max_chunk_size).unwrap_or(usize::MAX);
+        let to_write = std::cmp::min(buf.len(), max_chunk_size);
+        let chunk = &buf[..to_write];
+
+        let chunk_len = u32::try_from(to_write).expect("to_write is bounded by max_chunk_size");
+        self.stream.write_all(&chunk_len.to_le_bytes())?;
+        self.stream.write_all(chunk)?;
+
+        Ok(to_write)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_a_body_split_across_several_chunks() {
+        let payload = b"the quick brown fox jumps over the lazy dog";
+
+        let mut stream = Vec::new();
+        let mut writer = ChunkedBodyWriter::with_max_chunk_size(&mut stream, 5);
+        writer.write_all(payload).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = ChunkedBodyReader::new(stream.as_slice());
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn reader_rejects_a_chunk_larger_than_the_configured_maximum() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&10_u32.to_le_bytes()); // declares a 10-byte chunk
+        stream.extend_from_slice(&[0_u8; 10]);
+
+        let mut reader = ChunkedBodyReader::with_max_chunk_size(stream.as_slice(), 4);
+        let mut received = Vec::new();
+        let err = reader.read_to_end(&mut received).unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writer_treats_an_empty_write_as_a_no_op() {
+        let mut stream = Vec::new();
+        let mut writer = ChunkedBodyWriter::new(&mut stream);
+
+        let written = writer.write(&[]).unwrap();
+        assert_eq!(written, 0);
+        writer.finish().unwrap();
+
+        // Only the terminating zero-length chunk should have been written.
+        assert_eq!(stream, 0_u32.to_le_bytes());
+    }
+}
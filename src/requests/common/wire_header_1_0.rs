@@ -15,13 +15,13 @@
 
 //! This module defines and implements the raw wire protocol header frame for
 //! version 1.0 of the protocol.
+use crate::requests::common::chunked_body::CHUNKED_BODY_LEN;
 use crate::requests::common::MAGIC_NUMBER;
 use crate::requests::{ResponseStatus, Result};
 #[cfg(feature = "fuzz")]
 use arbitrary::Arbitrary;
 use log::error;
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
 use std::io::{Read, Write};
 
 const WIRE_PROTOCOL_VERSION_MAJ: u8 = 1;
@@ -34,7 +34,7 @@ const REQUEST_HDR_SIZE: u16 = 24;
 /// Serialisation and deserialisation are handled by `serde`, also in tune with the
 /// wire format (i.e. little-endian, native encoding).
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WireHeader {
     /// Provider ID value
     pub provider: u8,
@@ -54,8 +54,128 @@ pub struct WireHeader {
     pub opcode: u16,
     /// Response status of the request.
     pub status: u16,
+    /// Bytes trailing the fields known to this version of the header, present
+    /// when a peer advertising a greater `hdr_size` appended fields this crate
+    /// does not yet understand. Not part of the wire encoding of the fields
+    /// above: it is recovered separately from the leftover header bytes.
+    #[serde(skip)]
+    pub extra: Vec<u8>,
 }
 
+/// Describes how a particular `(maj, min)` wire-protocol version lays out its
+/// header, so that `read_from_stream`/`write_to_stream` can be taught new
+/// versions without hard-coding a single accepted version.
+struct HeaderVersion {
+    /// Major version this entry applies to.
+    maj: u8,
+    /// Minor version this entry applies to.
+    min: u8,
+    /// Size, in bytes, of the header content following the `hdr_size` field for
+    /// this version (version bytes included).
+    hdr_size: u16,
+    /// Deserialise the version-specific fields, with the leading version bytes
+    /// already stripped, into a `WireHeader`.
+    deserialize: fn(&[u8]) -> HeaderResult<WireHeader>,
+}
+
+/// Wire-protocol versions this crate knows how to parse.
+///
+/// `read_from_stream` dispatches on an entry's `hdr_size` and `deserialize`.
+/// `write_to_stream` takes only an entry's `hdr_size` and version bytes and
+/// always serialises the fields in the 1.0 layout.
+const VERSION_REGISTRY: &[HeaderVersion] = &[HeaderVersion {
+    maj: WIRE_PROTOCOL_VERSION_MAJ,
+    min: WIRE_PROTOCOL_VERSION_MIN,
+    hdr_size: REQUEST_HDR_SIZE,
+    deserialize: |bytes| bincode::deserialize(bytes).map_err(HeaderValidationError::Malformed),
+}];
+
+fn find_version(maj: u8, min: u8) -> Option<&'static HeaderVersion> {
+    VERSION_REGISTRY
+        .iter()
+        .find(|version| version.maj == maj && version.min == min)
+}
+
+/// Reasons why parsing a wire header out of a byte stream can fail, at a level
+/// of detail finer than the single `ResponseStatus::InvalidHeader` value the
+/// rest of the crate works with. Lets logs and test assertions pinpoint
+/// exactly which part of the frame was malformed.
+#[derive(Debug)]
+pub enum HeaderValidationError {
+    /// The magic number at the start of the frame did not match.
+    BadMagic {
+        /// The magic number we expected to read.
+        expected: u32,
+        /// The magic number we actually read.
+        got: u32,
+    },
+    /// The declared `hdr_size` was smaller than the two version bytes, or than
+    /// the parsed version requires.
+    UnexpectedHeaderSize {
+        /// The minimum header size required: 2 for the version bytes, or the
+        /// parsed version's header size.
+        expected: u16,
+        /// The header size actually declared on the wire.
+        got: u16,
+    },
+    /// The declared `(maj, min)` version is not one this crate knows how to parse.
+    UnsupportedVersion {
+        /// Major version declared on the wire.
+        maj: u8,
+        /// Minor version declared on the wire.
+        min: u8,
+    },
+    /// Reading from the stream failed (e.g. it ended) before the magic number,
+    /// `hdr_size` and the declared number of header bytes were all read.
+    TruncatedBody,
+    /// The header bytes were of the expected size but could not be unmarshalled
+    /// into the version's fields.
+    Malformed(bincode::Error),
+}
+
+impl std::fmt::Display for HeaderValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderValidationError::BadMagic { expected, got } => {
+                write!(f, "expected magic number {}, got {}", expected, got)
+            }
+            HeaderValidationError::UnexpectedHeaderSize { expected, got } => write!(
+                f,
+                "expected request header size of at least {}, got {}",
+                expected, got
+            ),
+            HeaderValidationError::UnsupportedVersion { maj, min } => {
+                write!(f, "unsupported wire protocol version {}.{}", maj, min)
+            }
+            HeaderValidationError::TruncatedBody => {
+                write!(f, "stream ended before the declared header size was read")
+            }
+            HeaderValidationError::Malformed(err) => write!(f, "malformed header bytes: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for HeaderValidationError {}
+
+/// Lossy conversion down to the `ResponseStatus` values callers across the
+/// wire protocol already know how to handle.
+impl From<HeaderValidationError> for ResponseStatus {
+    fn from(error: HeaderValidationError) -> Self {
+        match error {
+            HeaderValidationError::BadMagic { .. }
+            | HeaderValidationError::UnexpectedHeaderSize { .. } => ResponseStatus::InvalidHeader,
+            HeaderValidationError::UnsupportedVersion { .. } => {
+                ResponseStatus::WireProtocolVersionNotSupported
+            }
+            HeaderValidationError::TruncatedBody => ResponseStatus::ConnectionError,
+            HeaderValidationError::Malformed(_) => ResponseStatus::InvalidEncoding,
+        }
+    }
+}
+
+/// Result alias for operations that can fail with a [`HeaderValidationError`].
+pub type HeaderResult<T> = std::result::Result<T, HeaderValidationError>;
+
 impl WireHeader {
     /// Create a new raw wire header.
     ///
@@ -73,22 +193,36 @@ impl WireHeader {
             auth_len: 0,
             opcode: 0,
             status: 0,
+            extra: Vec::new(),
         }
     }
 
+    /// Whether `body_len` is the sentinel value signalling that the body is
+    /// framed as a sequence of chunks (see `chunked_body`) rather than a
+    /// single blob of `body_len` bytes.
+    pub fn is_chunked(&self) -> bool {
+        self.body_len == CHUNKED_BODY_LEN
+    }
+
     /// Serialise the request header and write the corresponding bytes to the given
-    /// stream.
+    /// stream, encoded for the given wire-protocol version.
     ///
     /// # Errors
+    /// - if `version` is not a version this crate knows how to emit,
+    /// `ResponseStatus::WireProtocolVersionNotSupported` is returned.
     /// - if marshalling the header fails, `ResponseStatus::InvalidEncoding` is returned.
     /// - if writing the header bytes fails, `ResponseStatus::ConnectionError` is returned.
-    pub fn write_to_stream<W: Write>(&self, stream: &mut W) -> Result<()> {
+    pub fn write_to_stream<W: Write>(&self, stream: &mut W, version: (u8, u8)) -> Result<()> {
+        let (version_maj, version_min) = version;
+        let header_version = find_version(version_maj, version_min)
+            .ok_or(ResponseStatus::WireProtocolVersionNotSupported)?;
+
         stream.write_all(&bincode::serialize(&MAGIC_NUMBER)?)?;
 
-        stream.write_all(&bincode::serialize(&REQUEST_HDR_SIZE)?)?;
+        stream.write_all(&bincode::serialize(&header_version.hdr_size)?)?;
 
-        stream.write_all(&bincode::serialize(&WIRE_PROTOCOL_VERSION_MAJ)?)?;
-        stream.write_all(&bincode::serialize(&WIRE_PROTOCOL_VERSION_MIN)?)?;
+        stream.write_all(&bincode::serialize(&version_maj)?)?;
+        stream.write_all(&bincode::serialize(&version_min)?)?;
 
         stream.write_all(&bincode::serialize(&self)?)?;
 
@@ -97,47 +231,210 @@ impl WireHeader {
 
     /// Deserialise a request header from the given stream.
     ///
+    /// The header's declared `(maj, min)` version is looked up in
+    /// [`VERSION_REGISTRY`](self) and dispatched to that version's layout, so
+    /// any registered version other than 1.0 parses correctly too.
+    ///
+    /// Returns a [`HeaderValidationError`] rather than the crate-wide
+    /// `ResponseStatus` so that callers can tell exactly which part of the
+    /// header frame was malformed; use `ResponseStatus::from` to fall back to
+    /// the coarser status when that's all a caller needs.
+    ///
     /// # Errors
-    /// - if either the magic number or the header size are invalid values,
-    /// `ResponseStatus::InvalidHeader` is returned.
-    /// - if reading the fields after magic number and header size fails,
-    /// `ResponseStatus::ConnectionError` is returned
-    ///     - the read may fail due to a timeout if not enough bytes are
-    ///     sent across
+    /// - if the magic number is an invalid value, [`HeaderValidationError::BadMagic`].
+    /// - if reading the magic number, the header size or the fields after them
+    /// fails, [`HeaderValidationError::TruncatedBody`]
+    ///     - may happen due to a timeout if not enough bytes are sent across
+    /// - if the wire protocol version used is not a registered version,
+    /// [`HeaderValidationError::UnsupportedVersion`].
+    /// - if the declared header size is smaller than the two version bytes, or
+    /// than the one expected for the parsed version,
+    /// [`HeaderValidationError::UnexpectedHeaderSize`]. A
+    /// declared size that is *larger* is tolerated: the known fields are
+    /// parsed as usual and the trailing bytes are returned in
+    /// [`WireHeader::extra`], so that a peer appending fields we don't
+    /// understand yet does not get rejected.
     /// - if the parsed bytes cannot be unmarshalled into the contained fields,
-    /// `ResponseStatus::InvalidEncoding` is returned.
-    /// - if the wire protocol version used is different than 1.0
-    pub fn read_from_stream<R: Read>(mut stream: &mut R) -> Result<WireHeader> {
-        let magic_number = get_from_stream!(stream, u32);
+    /// [`HeaderValidationError::Malformed`].
+    pub fn read_from_stream<R: Read>(stream: &mut R) -> HeaderResult<WireHeader> {
+        let mut magic_bytes = [0_u8; 4];
+        stream
+            .read_exact(&mut magic_bytes)
+            .map_err(|_| HeaderValidationError::TruncatedBody)?;
+        let magic_number: u32 =
+            bincode::deserialize(&magic_bytes).map_err(HeaderValidationError::Malformed)?;
         if magic_number != MAGIC_NUMBER {
             error!(
                 "Expected magic number {}, got {}",
                 MAGIC_NUMBER, magic_number
             );
-            return Err(ResponseStatus::InvalidHeader);
+            return Err(HeaderValidationError::BadMagic {
+                expected: MAGIC_NUMBER,
+                got: magic_number,
+            });
         }
 
-        let hdr_size = get_from_stream!(stream, u16);
-        let mut bytes = vec![0_u8; usize::try_from(hdr_size)?];
-        stream.read_exact(&mut bytes)?;
-        if hdr_size != REQUEST_HDR_SIZE {
+        let mut hdr_size_bytes = [0_u8; 2];
+        stream
+            .read_exact(&mut hdr_size_bytes)
+            .map_err(|_| HeaderValidationError::TruncatedBody)?;
+        let hdr_size: u16 =
+            bincode::deserialize(&hdr_size_bytes).map_err(HeaderValidationError::Malformed)?;
+
+        let mut bytes = vec![0_u8; usize::from(hdr_size)];
+        stream
+            .read_exact(&mut bytes)
+            .map_err(|_| HeaderValidationError::TruncatedBody)?;
+
+        // The version bytes are the first two bytes of the header content; a
+        // peer declaring a `hdr_size` smaller than that can't be dispatched to
+        // any version at all.
+        const VERSION_BYTES: u16 = 2;
+        if hdr_size < VERSION_BYTES {
             error!(
-                "Expected request header size {}, got {}",
-                REQUEST_HDR_SIZE, hdr_size
+                "Expected request header size of at least {}, got {}",
+                VERSION_BYTES, hdr_size
             );
-            return Err(ResponseStatus::InvalidHeader);
+            return Err(HeaderValidationError::UnexpectedHeaderSize {
+                expected: VERSION_BYTES,
+                got: hdr_size,
+            });
         }
 
-        let version_maj = bytes.remove(0); // first byte after hdr length is version maj
-        let version_min = bytes.remove(0); // second byte after hdr length is version min
-        if version_maj != WIRE_PROTOCOL_VERSION_MAJ || version_min != WIRE_PROTOCOL_VERSION_MIN {
+        let version_maj = bytes[0]; // first byte after hdr length is version maj
+        let version_min = bytes[1]; // second byte after hdr length is version min
+        let header_version = find_version(version_maj, version_min).ok_or_else(|| {
             error!(
-                "Expected wire protocol version {}.{}, got {}.{} instead",
-                WIRE_PROTOCOL_VERSION_MAJ, WIRE_PROTOCOL_VERSION_MIN, version_maj, version_min
+                "Unsupported wire protocol version {}.{}",
+                version_maj, version_min
             );
-            return Err(ResponseStatus::WireProtocolVersionNotSupported);
+            HeaderValidationError::UnsupportedVersion {
+                maj: version_maj,
+                min: version_min,
+            }
+        })?;
+
+        if hdr_size < header_version.hdr_size {
+            error!(
+                "Expected request header size of at least {} for version {}.{}, got {}",
+                header_version.hdr_size, version_maj, version_min, hdr_size
+            );
+            return Err(HeaderValidationError::UnexpectedHeaderSize {
+                expected: header_version.hdr_size,
+                got: hdr_size,
+            });
         }
 
-        Ok(bincode::deserialize(&bytes)?)
+        let extra = bytes.split_off(usize::from(header_version.hdr_size));
+        let mut header = (header_version.deserialize)(&bytes[2..])?;
+        header.extra = extra;
+
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "testing")]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    fn encode_1_0(header: &WireHeader) -> Vec<u8> {
+        let mut stream = Vec::new();
+        header
+            .write_to_stream(
+                &mut stream,
+                (WIRE_PROTOCOL_VERSION_MAJ, WIRE_PROTOCOL_VERSION_MIN),
+            )
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn round_trips_a_1_0_header() {
+        let mut header = WireHeader::new();
+        header.provider = 1;
+        header.session = 42;
+        header.opcode = 7;
+
+        let bytes = encode_1_0(&header);
+        let parsed = WireHeader::read_from_stream(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(parsed.provider, header.provider);
+        assert_eq!(parsed.session, header.session);
+        assert_eq!(parsed.opcode, header.opcode);
+        assert!(parsed.extra.is_empty());
+    }
+
+    #[test]
+    fn rejects_an_unregistered_version() {
+        let mut bytes = encode_1_0(&WireHeader::new());
+        // Version maj/min are the first two bytes of the header content,
+        // right after the magic number and hdr_size fields.
+        bytes[6] = 9;
+        bytes[7] = 9;
+
+        let err = WireHeader::read_from_stream(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(
+            err,
+            HeaderValidationError::UnsupportedVersion { maj: 9, min: 9 }
+        ));
+    }
+
+    #[test]
+    fn tolerates_a_larger_than_known_header_size() {
+        let mut bytes = encode_1_0(&WireHeader::new());
+        // Bump the declared hdr_size and append the bytes a newer peer would
+        // have tacked on for fields we don't understand yet.
+        let appended = [0xAA_u8, 0xBB, 0xCC];
+        let new_hdr_size = REQUEST_HDR_SIZE + u16::try_from(appended.len()).unwrap();
+        bytes[4..6].copy_from_slice(&bincode::serialize(&new_hdr_size).unwrap());
+        bytes.extend_from_slice(&appended);
+
+        let parsed = WireHeader::read_from_stream(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(parsed.extra, appended);
+    }
+
+    #[test]
+    fn rejects_a_header_size_too_short_for_the_version_bytes_instead_of_panicking() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&bincode::serialize(&MAGIC_NUMBER).unwrap());
+        stream.extend_from_slice(&bincode::serialize(&0_u16).unwrap()); // hdr_size = 0
+
+        let err = WireHeader::read_from_stream(&mut Cursor::new(stream)).unwrap_err();
+        assert!(matches!(
+            err,
+            HeaderValidationError::UnexpectedHeaderSize {
+                expected: 2,
+                got: 0
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_a_bad_magic_number() {
+        let mut bytes = encode_1_0(&WireHeader::new());
+        bytes[0..4].copy_from_slice(&bincode::serialize(&(MAGIC_NUMBER.wrapping_add(1))).unwrap());
+
+        let err = WireHeader::read_from_stream(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(
+            err,
+            HeaderValidationError::BadMagic {
+                expected,
+                got
+            } if expected == MAGIC_NUMBER && got == MAGIC_NUMBER.wrapping_add(1)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_stream_truncated_before_the_declared_header_size() {
+        let bytes = encode_1_0(&WireHeader::new());
+        // Keep the magic number and hdr_size, but drop everything after them.
+        let truncated = bytes[..6].to_vec();
+
+        let err = WireHeader::read_from_stream(&mut Cursor::new(truncated)).unwrap_err();
+        assert!(matches!(err, HeaderValidationError::TruncatedBody));
     }
 }